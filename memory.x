@@ -1,5 +1,6 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 32K
+  /* The last 2K page holds the pattern library, see src/patterns.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 30K
   RAM : ORIGIN = 0x20000000, LENGTH = 8K
 }
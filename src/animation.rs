@@ -0,0 +1,210 @@
+//! Keyframe animations uploaded by the bus master.
+//!
+//! Every keyframe paints its color onto the pixels in its mask, fading in from the
+//! previous frame and then holding. Pixels outside the mask keep their previous color.
+//! Playback is a pure function of the tick since the animation started.
+
+use crate::led::{blend, Color, Frame};
+use crate::LED_COUNT;
+use core::str::FromStr;
+use heapless::Vec;
+
+pub const MAX_KEYFRAMES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Keyframe {
+    /// Color packed as `0xRRGGBBWW`.
+    pub color: u32,
+    /// Bit `n` selects pixel `n`.
+    pub mask: u32,
+    /// Ticks to fade in from the previous frame.
+    pub fade: u16,
+    /// Ticks to hold the frame after fading in.
+    pub hold: u16,
+}
+
+impl Keyframe {
+    const fn ticks(&self) -> u32 {
+        self.fade as u32 + self.hold as u32
+    }
+
+    fn paint(&self, frame: &Frame) -> Frame {
+        let color = Color::Rgbw(self.color).into();
+        let mut frame = *frame;
+        for (n, pixel) in frame.iter_mut().enumerate() {
+            if n < 32 && self.mask & (1 << n) != 0 {
+                *pixel = color;
+            }
+        }
+        frame
+    }
+}
+
+/// One keyframe as sent over the bus, hex fields separated by commas:
+/// `index,color,mask,fade,hold[,loop]`.
+///
+/// Keyframes must be sent in order; index 0 starts a new animation. The optional last
+/// field makes the animation continue at the given keyframe after the end, otherwise it
+/// holds its last frame.
+#[derive(Debug, Clone, Copy)]
+pub struct Upload {
+    index: u8,
+    keyframe: Keyframe,
+    loop_from: Option<u8>,
+}
+
+#[derive(Debug)]
+pub struct BadUpload;
+
+fn hex<T: TryFrom<u32>>(field: Option<&str>) -> Result<T, BadUpload> {
+    let value = u32::from_str_radix(field.ok_or(BadUpload)?, 16).map_err(|_| BadUpload)?;
+    value.try_into().map_err(|_| BadUpload)
+}
+
+impl FromStr for Upload {
+    type Err = BadUpload;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(',');
+        let index = hex(fields.next())?;
+        let color = hex(fields.next())?;
+        let mask = hex(fields.next())?;
+        let fade = hex(fields.next())?;
+        let hold = hex(fields.next())?;
+        let loop_from = match fields.next() {
+            Some(field) => Some(hex(Some(field))?),
+            None => None,
+        };
+        Ok(Self {
+            index,
+            keyframe: Keyframe {
+                color,
+                mask,
+                fade,
+                hold,
+            },
+            loop_from,
+        })
+    }
+}
+
+pub struct Animation {
+    keyframes: Vec<Keyframe, MAX_KEYFRAMES>,
+    loop_from: Option<u8>,
+}
+
+impl Animation {
+    pub const fn new() -> Self {
+        Self {
+            keyframes: Vec::new(),
+            loop_from: None,
+        }
+    }
+
+    /// Whether `load` would take the keyframe: it must be in order, fit and not loop to
+    /// a keyframe that does not exist.
+    pub fn accepts(&self, upload: &Upload) -> bool {
+        let next = if upload.index == 0 {
+            0
+        } else {
+            self.keyframes.len()
+        };
+        upload.index as usize == next
+            && next < MAX_KEYFRAMES
+            && upload.loop_from.is_none_or(|n| n <= upload.index)
+    }
+
+    /// Adds an uploaded keyframe. Returns `false` if it is not accepted.
+    pub fn load(&mut self, upload: Upload) -> bool {
+        if !self.accepts(&upload) {
+            return false;
+        }
+        if upload.index == 0 {
+            self.keyframes.clear();
+        }
+        let _ = self.keyframes.push(upload.keyframe);
+        self.loop_from = upload.loop_from;
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keyframes.is_empty()
+    }
+
+    pub fn sequence(&self) -> Sequence<'_> {
+        Sequence {
+            keyframes: &self.keyframes,
+            loop_from: self.loop_from,
+        }
+    }
+}
+
+/// Keyframes ready for playback, either from RAM or from the pattern library.
+#[derive(Clone, Copy)]
+pub struct Sequence<'a> {
+    keyframes: &'a [Keyframe],
+    loop_from: Option<u8>,
+}
+
+impl<'a> Sequence<'a> {
+    pub const fn new(keyframes: &'a [Keyframe], loop_from: Option<u8>) -> Self {
+        Self {
+            keyframes,
+            loop_from,
+        }
+    }
+
+    pub const fn keyframes(&self) -> &'a [Keyframe] {
+        self.keyframes
+    }
+
+    pub const fn loop_from(&self) -> Option<u8> {
+        self.loop_from
+    }
+
+    /// Length of one pass through all keyframes.
+    pub fn ticks(&self) -> u32 {
+        self.ticks_until(self.keyframes.len())
+    }
+
+    fn ticks_until(&self, index: usize) -> u32 {
+        let index = index.min(self.keyframes.len());
+        self.keyframes[..index].iter().map(Keyframe::ticks).sum()
+    }
+
+    pub fn frame_for_tick(&self, tick: u32) -> Frame {
+        let total = self.ticks();
+        let mut tick = tick;
+        if tick >= total {
+            tick = match self.loop_from {
+                Some(start) => {
+                    let intro = self.ticks_until(start as usize);
+                    let length = total - intro;
+                    if length > 0 {
+                        intro + (tick - intro) % length
+                    } else {
+                        total
+                    }
+                }
+                None => total,
+            };
+        }
+
+        let mut frame: Frame = [Color::Off.into(); LED_COUNT];
+        for keyframe in self.keyframes.iter() {
+            let target = keyframe.paint(&frame);
+            let fade = keyframe.fade as u32;
+            if tick < fade {
+                return blend(&frame, &target, (tick, fade));
+            }
+            tick -= fade;
+            if tick < keyframe.hold as u32 {
+                return target;
+            }
+            tick -= keyframe.hold as u32;
+            frame = target;
+        }
+        frame
+    }
+}
@@ -5,7 +5,8 @@ use panic_semihosting as _;
 #[app(device = crate::hal::stm32, peripherals = true, dispatchers = [EXTI0_1, EXTI2_3, EXTI4_15, I2C1, I2C2, SPI1, SPI2])]
 mod app {
     use crate::adc::{AdcReader, Button};
-    use crate::command::Command;
+    use crate::boot::BootReason;
+    use crate::command::{Ack, Command, Query};
     use crate::hal::{
         gpio::{gpioa::PA13, Analog},
         prelude::*,
@@ -15,19 +16,42 @@ mod app {
         watchdog::IndependedWatchdog,
     };
     use crate::led::{Color, Leds, Mode};
+    use crate::patterns::Library;
     use crate::rs485::Rs485;
+    use crate::ws2812::Ws2812Dma;
     use core::mem::replace;
     use cortex_m::asm;
+    use heapless::Deque;
     use protocol::outgoing::Message;
     use rtic::pend;
     use systick_monotonic::{fugit::ExtU64, Systick};
 
+    /// Commands received but not yet applied by `led_work`.
+    const COMMAND_QUEUE: usize = 4;
+    /// Acknowledgements waiting for a token slot, one is sent per message.
+    const ACK_QUEUE: usize = 4;
+
+    /// Queues an acknowledgement, dropping the oldest one if there is no room.
+    fn push_ack(acks: &mut Deque<Ack, ACK_QUEUE>, ack: Ack) {
+        if acks.is_full() {
+            acks.pop_front();
+        }
+        let _ = acks.push_back(ack);
+    }
+
     #[shared]
     struct Shared {
         voltage: u16,
         temperature: i16,
+        derating: bool,
+        self_testing: bool,
+        boot_reason: Option<BootReason>,
         button: Option<Button>,
-        command: Option<Command>,
+        commands: Deque<Command, COMMAND_QUEUE>,
+        acks: Deque<Ack, ACK_QUEUE>,
+        query: Option<Query>,
+        led_status: (Mode, u8),
+        rejected: u16,
         timer_flag: bool,
         ping_flag: bool,
         uptime: u32,
@@ -35,7 +59,7 @@ mod app {
 
     #[local]
     struct Local {
-        led: Leds,
+        led: Leds<Ws2812Dma>,
         adc: AdcReader<PA13<Analog>>,
         rs485: Rs485,
         dog: IndependedWatchdog,
@@ -50,6 +74,8 @@ mod app {
         let core = cx.core;
         let dev = cx.device;
 
+        let boot_reason = BootReason::read(&dev.RCC);
+
         // Configure system clocks
         let pll_cfg = PllConfig::with_hsi(2, 16, 2); // 64 MHz
         let rcc_cfg = rcc::Config::pll().pll_cfg(pll_cfg);
@@ -104,9 +130,14 @@ mod app {
                 &mut rcc,
             )
             .expect("Can't initialize LED UART");
-        let mut led = Leds::new(led);
+        let library = Library::new(dev.FLASH);
+        let mut led = Leds::new(Ws2812Dma::new(led, dma.ch2), library);
         delay.delay(1_u32.millis());
-        led.set_mode(Mode::Blink(Color::Yellow, 1_u32.secs()));
+        led.set_mode(Mode::Blink(Color::Yellow, 1_u32.secs()), None);
+        #[cfg(feature = "self-test")]
+        led.show_effect(Mode::SelfTest, 1, None);
+        // Only now, so that the self-test does not start with a fade from black.
+        led.set_fade(300_u32.millis());
 
         // Configure buttons via ADC
         let buttons = gpioa.pa13; // ADC1_IN17
@@ -120,8 +151,15 @@ mod app {
         let shared = Shared {
             voltage: 0,
             temperature: -2731,
+            derating: false,
+            self_testing: cfg!(feature = "self-test"),
+            boot_reason: Some(boot_reason),
             button: None,
-            command: None,
+            commands: Deque::new(),
+            acks: Deque::new(),
+            query: None,
+            led_status: (Mode::Constant(Color::Off), 0),
+            rejected: 0,
             ping_flag: false,
             timer_flag: false,
             uptime: 0,
@@ -143,12 +181,38 @@ mod app {
         (shared, local, init::Monotonics(mono))
     }
 
-    #[task(priority = 2, local = [led], shared = [command])]
+    #[task(priority = 2, local = [led, last_ack: Option<Ack> = None], shared = [commands, acks, led_status, rejected, voltage, temperature, derating, self_testing])]
     fn led_work(mut cx: led_work::Context) {
-        let cmd = cx.shared.command.lock(|cmd| cmd.take());
-        if let Some(cmd) = cmd {
-            cmd.apply(&mut cx.local.led);
+        while let Some(cmd) = cx.shared.commands.lock(|c| c.pop_front()) {
+            let last_ack = *cx.local.last_ack;
+            let ack = match last_ack.filter(|a| cmd.sequence() == Some(a.sequence)) {
+                // The master retries when our ack got lost; answer again, but run it once.
+                Some(ack) => Some(ack),
+                None => {
+                    let accepted = cmd.apply(&mut cx.local.led);
+                    if !accepted {
+                        cx.shared.rejected.lock(|r| *r = r.wrapping_add(1));
+                    }
+                    let ack = cmd.ack(accepted);
+                    *cx.local.last_ack = ack.or(last_ack);
+                    ack
+                }
+            };
+            if let Some(ack) = ack {
+                cx.shared.acks.lock(|acks| push_ack(acks, ack));
+            }
         }
+        let mode = cx.local.led.mode();
+        let intensity = cx.local.led.intensity().get() - 1;
+        cx.shared.led_status.lock(|s| *s = (mode, intensity));
+        let voltage = cx.shared.voltage.lock(|v| *v);
+        cx.local.led.set_supply_voltage(voltage);
+        let temperature = cx.shared.temperature.lock(|t| *t);
+        cx.local.led.set_temperature(temperature);
+        let derating = cx.local.led.is_derating();
+        cx.shared.derating.lock(|d| *d = derating);
+        let self_testing = cx.local.led.is_self_testing();
+        cx.shared.self_testing.lock(|s| *s = self_testing);
         cx.local.led.tick();
         led_work::spawn_after(cx.local.led.period()).expect("Can't respawn led_work");
     }
@@ -189,25 +253,62 @@ mod app {
         cx.local.uptimer.clear_irq();
     }
 
-    #[task(priority = 4, binds = USART1, local = [dog, rs485], shared = [button, voltage, temperature, ping_flag, timer_flag, uptime, command])]
+    #[task(priority = 4, binds = USART1, local = [dog, rs485], shared = [button, voltage, temperature, derating, self_testing, boot_reason, ping_flag, timer_flag, uptime, commands, acks, query, led_status, rejected])]
     fn rs485_interrupt(mut cx: rs485_interrupt::Context) {
         cx.local.dog.feed();
+        let bus_errors = cx.local.rs485.errors();
         let cmd =
             cx.local
                 .rs485
                 .interrupt(cx.shared.timer_flag.lock(|f| replace(f, false)), |buf| {
                     let button = cx.shared.button.lock(|b| b.take());
                     let ping_flag = cx.shared.ping_flag.lock(|f| replace(f, false));
-                    if button.is_some() || ping_flag {
+                    // Reported once, as soon as the self-test is over, or right away on
+                    // builds without it.
+                    let boot_reason = if cx.shared.self_testing.lock(|s| *s) {
+                        None
+                    } else {
+                        cx.shared.boot_reason.lock(|r| r.take())
+                    };
+                    let ack = cx.shared.acks.lock(|a| a.pop_front());
+                    let query = cx.shared.query.lock(|q| q.take());
+                    if button.is_some()
+                        || ping_flag
+                        || boot_reason.is_some()
+                        || ack.is_some()
+                        || query.is_some()
+                    {
                         let voltage = cx.shared.voltage.lock(|v| *v);
                         let temperature = cx.shared.temperature.lock(|t| *t);
                         let uptime = cx.shared.uptime.lock(|t| *t);
+                        let derating = cx.shared.derating.lock(|d| *d);
+                        let status = (query == Some(Query::Status))
+                            .then(|| cx.shared.led_status.lock(|s| *s));
+                        let mode = status.map(|(mode, _)| mode);
+                        let config = query == Some(Query::Config);
+                        let errors = query == Some(Query::Errors);
                         let message = Message {
                             sender: crate::DEVICE_ADDRESS,
                             button: button.map(|b| b as u8),
                             temperature,
                             voltage,
                             uptime,
+                            derating,
+                            self_test: cfg!(feature = "self-test") && boot_reason.is_some(),
+                            boot_reason: boot_reason.map(|r| r as u8),
+                            ack: ack.map(|a| a.sequence),
+                            rejected: ack.is_some_and(|a| !a.accepted),
+                            version: (query == Some(Query::Version))
+                                .then_some(env!("CARGO_PKG_VERSION")),
+                            led_mode: mode.map(|m| m.index()),
+                            led_pattern: mode.and_then(|m| m.pattern()),
+                            led_rgbw: mode.and_then(|m| m.color()).map(Color::rgbw),
+                            led_period: mode.and_then(|m| m.period()).map(|p| p.to_millis()),
+                            led_intensity: status.map(|(_, intensity)| intensity),
+                            led_count: config.then_some(crate::LED_COUNT as u8),
+                            led_budget: config.then_some(crate::LED_BUDGET_MA as u16),
+                            bus_errors: errors.then_some(bus_errors),
+                            rejected_commands: errors.then(|| cx.shared.rejected.lock(|r| *r)),
                         };
                         message.to_bytes(buf);
                         true
@@ -216,9 +317,16 @@ mod app {
                     }
                 });
         if let Some(c) = cmd {
-            cx.shared.command.lock(|cmd| {
-                *cmd = Some(c);
-            });
+            if let Some(query) = c.query() {
+                cx.shared.query.lock(|q| *q = Some(query));
+            }
+            // With the queue full, the master gets a rejection and can send it again.
+            if let Err(c) = cx.shared.commands.lock(|cmds| cmds.push_back(c)) {
+                cx.shared.rejected.lock(|r| *r = r.wrapping_add(1));
+                if let Some(ack) = c.ack(false) {
+                    cx.shared.acks.lock(|acks| push_ack(acks, ack));
+                }
+            }
         }
     }
 
@@ -0,0 +1,40 @@
+//! Reason for the last reset, from the RCC reset flags.
+
+use crate::hal::stm32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BootReason {
+    PowerOn = 0,
+    Pin = 1,
+    Software = 2,
+    IndependentWatchdog = 3,
+    WindowWatchdog = 4,
+    LowPower = 5,
+    OptionBytes = 6,
+}
+
+impl BootReason {
+    /// Reads and clears the reset flags. The pin flag is set by every reset, so it only
+    /// counts when no other flag is.
+    pub fn read(rcc: &stm32::RCC) -> Self {
+        let csr = rcc.csr.read();
+        let reason = if csr.lpwrrstf().bit_is_set() {
+            Self::LowPower
+        } else if csr.wwdgrstf().bit_is_set() {
+            Self::WindowWatchdog
+        } else if csr.iwdgrstf().bit_is_set() {
+            Self::IndependentWatchdog
+        } else if csr.sftrstf().bit_is_set() {
+            Self::Software
+        } else if csr.oblrstf().bit_is_set() {
+            Self::OptionBytes
+        } else if csr.pwrrstf().bit_is_set() {
+            Self::PowerOn
+        } else {
+            Self::Pin
+        };
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        reason
+    }
+}
@@ -1,73 +1,372 @@
-use crate::led::{Color, Intensity, Leds, Mode};
+use crate::animation::Upload;
+use crate::easing::Easing;
+use crate::led::{Blend, Color, Intensity, Layer, Leds, Mode, Pixel};
+use crate::{DEVICE_ADDRESS, DEVICE_GROUPS, MAX_PERIOD_MS, MIN_PERIOD_MS};
 use fugit::{Duration, ExtU32};
 use protocol::incoming::Message;
+use smart_leds::SmartLedsWrite;
 
 #[derive(Debug)]
 pub struct Command {
+    background: Option<Option<Mode>>,
     mode: Option<Mode>,
-    effect: Option<Mode>,
+    expiry: Option<Duration<u32, 1, 100>>,
+    effect: Option<(Mode, u8, Option<Duration<u32, 1, 100>>)>,
     intensity: Option<Intensity>,
+    keyframe: Option<Upload>,
+    store: Option<u8>,
+    blend: Option<(Layer, Blend)>,
+    sequence: Option<u16>,
+    rejected: bool,
+    query: Option<Query>,
 }
 
-trait FromLetter: Sized {
-    fn from_letter(letter: char) -> Option<Self>;
+/// Result of a command that carried a sequence number, echoed back to the master.
+#[derive(Debug, Clone, Copy)]
+pub struct Ack {
+    pub sequence: u16,
+    pub accepted: bool,
 }
 
-impl FromLetter for Mode {
-    fn from_letter(letter: char) -> Option<Self> {
-        match letter {
-            'R' => Some(Mode::Constant(Color::Red)),
-            'G' => Some(Mode::Constant(Color::Green)),
-            'B' => Some(Mode::Constant(Color::Blue)),
-            'W' => Some(Mode::Constant(Color::White)),
-            'Y' => Some(Mode::Constant(Color::Yellow)),
-            'M' => Some(Mode::Constant(Color::Magenta)),
-            'C' => Some(Mode::Constant(Color::Cyan)),
-            'r' => Some(Mode::Glow(Color::Red, slow_glow())),
-            'g' => Some(Mode::Glow(Color::Green, slow_glow())),
-            'b' => Some(Mode::Glow(Color::Blue, slow_glow())),
-            'w' => Some(Mode::Glow(Color::White, slow_glow())),
-            'y' => Some(Mode::Glow(Color::Yellow, slow_glow())),
-            'm' => Some(Mode::Glow(Color::Magenta, slow_glow())),
-            'c' => Some(Mode::Glow(Color::Cyan, slow_glow())),
+/// Information the master can ask a single node for. The node answers in its next
+/// token slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Version,
+    Status,
+    Config,
+    Errors,
+}
+
+impl Query {
+    pub const fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Self::Version),
+            1 => Some(Self::Status),
+            2 => Some(Self::Config),
+            3 => Some(Self::Errors),
             _ => None,
         }
     }
 }
 
-fn slow_glow() -> Duration<u32, 1, 100> {
-    3000_u32.millis()
+pub(crate) trait FromLetter: Sized {
+    fn from_letter(letter: char) -> Option<Self>;
+}
+
+const SLOW_GLOW: Duration<u32, 1, 100> = Duration::<u32, 1, 100>::millis(3000);
+const FAST_GLOW: Duration<u32, 1, 100> = Duration::<u32, 1, 100>::millis(800);
+const SLOW_BLINK: Duration<u32, 1, 100> = Duration::<u32, 1, 100>::secs(3);
+const FAST_BLINK: Duration<u32, 1, 100> = Duration::<u32, 1, 100>::secs(1);
+
+const fn ms(millis: u32) -> Duration<u32, 1, 100> {
+    Duration::<u32, 1, 100>::millis(millis)
+}
+
+/// Converts a period or duration from the bus, in milliseconds. Values the LED tick
+/// cannot run are rejected.
+fn checked_ms(millis: u32) -> Option<Duration<u32, 1, 100>> {
+    if (MIN_PERIOD_MS..=MAX_PERIOD_MS).contains(&millis) {
+        Some(ms(millis))
+    } else {
+        None
+    }
 }
 
-fn fast_glow() -> Duration<u32, 1, 100> {
-    800_u32.millis()
+/// Letters understood as mode and as effect. Colored groups are in the order red,
+/// green, blue, white, yellow, magenta, cyan. Library patterns are selected by number
+/// instead.
+#[rustfmt::skip]
+const VOCABULARY: [(char, Mode); 45] = {
+    use Color::*;
+    use Mode::*;
+    [
+        ('O', Constant(Off)),
+        ('R', Constant(Red)),
+        ('G', Constant(Green)),
+        ('B', Constant(Blue)),
+        ('W', Constant(White)),
+        ('Y', Constant(Yellow)),
+        ('M', Constant(Magenta)),
+        ('C', Constant(Cyan)),
+        ('r', Glow(Red, SLOW_GLOW, Easing::Linear)),
+        ('g', Glow(Green, SLOW_GLOW, Easing::Linear)),
+        ('b', Glow(Blue, SLOW_GLOW, Easing::Linear)),
+        ('w', Glow(White, SLOW_GLOW, Easing::Linear)),
+        ('y', Glow(Yellow, SLOW_GLOW, Easing::Linear)),
+        ('m', Glow(Magenta, SLOW_GLOW, Easing::Linear)),
+        ('c', Glow(Cyan, SLOW_GLOW, Easing::Linear)),
+        ('1', Glow(Red, FAST_GLOW, Easing::Linear)),
+        ('2', Glow(Green, FAST_GLOW, Easing::Linear)),
+        ('3', Glow(Blue, FAST_GLOW, Easing::Linear)),
+        ('4', Glow(White, FAST_GLOW, Easing::Linear)),
+        ('5', Glow(Yellow, FAST_GLOW, Easing::Linear)),
+        ('6', Glow(Magenta, FAST_GLOW, Easing::Linear)),
+        ('7', Glow(Cyan, FAST_GLOW, Easing::Linear)),
+        ('A', Blink(Red, FAST_BLINK)),
+        ('D', Blink(Green, FAST_BLINK)),
+        ('E', Blink(Blue, FAST_BLINK)),
+        ('F', Blink(White, FAST_BLINK)),
+        ('H', Blink(Yellow, FAST_BLINK)),
+        ('I', Blink(Magenta, FAST_BLINK)),
+        ('J', Blink(Cyan, FAST_BLINK)),
+        ('K', Blink(Red, SLOW_BLINK)),
+        ('L', Blink(Green, SLOW_BLINK)),
+        ('N', Blink(Blue, SLOW_BLINK)),
+        ('P', Blink(White, SLOW_BLINK)),
+        ('Q', Blink(Yellow, SLOW_BLINK)),
+        ('S', Blink(Magenta, SLOW_BLINK)),
+        ('T', Blink(Cyan, SLOW_BLINK)),
+        ('>', Chase(White, ms(1000), 3)),
+        ('<', Chase(White, ms(3000), 3)),
+        ('=', Rainbow(ms(6000), false)),
+        ('~', Rainbow(ms(6000), true)),
+        ('^', Custom),
+        ('|', Pulse(White, ms(1000), ms(100), 1)),
+        (':', Pulse(White, ms(1500), ms(200), 2)),
+        (';', Pulse(Red, ms(1200), ms(100), 2)),
+        ('?', SelfTest),
+    ]
+};
+
+impl FromLetter for Mode {
+    fn from_letter(letter: char) -> Option<Self> {
+        VOCABULARY
+            .iter()
+            .find(|(l, _)| *l == letter)
+            .map(|(_, mode)| *mode)
+    }
 }
 
 impl Command {
-    pub fn apply(&self, leds: &mut Leds) {
+    /// Applies the command and returns whether it was accepted.
+    pub fn apply<L: SmartLedsWrite<Color = Pixel>>(&self, leds: &mut Leds<L>) -> bool {
+        !self.rejected && self.execute(leds)
+    }
+
+    /// The acknowledgement to send if the master asked for one.
+    pub fn ack(&self, accepted: bool) -> Option<Ack> {
+        self.sequence.map(|sequence| Ack { sequence, accepted })
+    }
+
+    pub fn sequence(&self) -> Option<u16> {
+        self.sequence
+    }
+
+    pub fn query(&self) -> Option<Query> {
+        self.query
+    }
+
+    fn execute<L: SmartLedsWrite<Color = Pixel>>(&self, leds: &mut Leds<L>) -> bool {
+        // Check everything that can fail first, so that a rejected command changes nothing.
+        if self.keyframe.is_some_and(|k| !leds.accepts_keyframe(&k))
+            || self.store.is_some_and(|i| !leds.can_store_pattern(i))
+            // An accepted keyframe is loaded before storing and fills the animation.
+            || (self.store.is_some() && self.keyframe.is_none() && !leds.has_animation())
+            || (self.effect.is_some() && !leds.can_show_effect())
+            || self.modes().any(|mode| !self.can_play(leds, mode))
+        {
+            return false;
+        }
+
+        if let Some(upload) = self.keyframe {
+            leds.load_keyframe(upload);
+        }
+
+        if let Some(index) = self.store {
+            // Only a flash error gets here.
+            if !leds.store_pattern(index) {
+                return false;
+            }
+        }
+
+        if let Some((layer, blend)) = self.blend {
+            leds.set_blend(layer, blend);
+        }
+
+        if let Some(background) = self.background {
+            leds.set_background(background);
+        }
+
         if let Some(mode) = self.mode {
-            leds.set_mode(mode);
+            leds.set_mode(mode, self.expiry);
         }
 
-        if let Some(effect) = self.effect {
-            leds.show_effect(effect);
+        let mut accepted = true;
+        if let Some((effect, repeat, duration)) = self.effect {
+            accepted = leds.show_effect(effect, repeat, duration);
         }
 
         if let Some(intensity) = self.intensity {
             leds.set_intensity(intensity);
         }
+
+        accepted
+    }
+
+    /// Whether `leds` can play the mode once the keyframe and store parts of the command
+    /// are done: they come first, so the keyframe fills the custom animation and a
+    /// stored pattern is installed.
+    fn can_play<L: SmartLedsWrite<Color = Pixel>>(&self, leds: &Leds<L>, mode: Mode) -> bool {
+        match mode {
+            Mode::Custom if self.keyframe.is_some() => true,
+            Mode::Pattern(index) if self.store == Some(index) => true,
+            mode => leds.can_play(mode),
+        }
+    }
+
+    /// Every mode the command shows, on any layer.
+    fn modes(&self) -> impl Iterator<Item = Mode> {
+        [
+            self.background.flatten(),
+            self.mode,
+            self.effect.map(|(effect, ..)| effect),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Whether the message is meant for this node: addressed to it, to one of its groups
+    /// or to everyone. Messages without a destination or group are broadcasts.
+    fn is_for_me(message: &Message) -> bool {
+        match (message.destination, message.group) {
+            (Some(destination), _) => destination == DEVICE_ADDRESS,
+            (None, Some(group)) => DEVICE_GROUPS.contains(&group),
+            (None, None) => true,
+        }
     }
 
     pub fn from_rs485(message: Message) -> Option<Self> {
-        if message.color.is_some() || message.effect.is_some() || message.intensity.is_some() {
-            let mode = message.color.and_then(Mode::from_letter);
-            let effect = message.effect.and_then(Mode::from_letter);
+        if !Self::is_for_me(&message) {
+            return None;
+        }
+        // A sequence number alone makes a command, so that the master always gets an
+        // answer to it.
+        if message.sequence.is_some()
+            || message.color.is_some()
+            || message.rgbw.is_some()
+            || message.hue.is_some()
+            || message.saturation.is_some()
+            || message.easing.is_some()
+            || message.period.is_some()
+            || message.duration.is_some()
+            || message.repeat.is_some()
+            || message.timeout.is_some()
+            || message.effect.is_some()
+            || message.intensity.is_some()
+            || message.keyframe.is_some()
+            || message.pattern.is_some()
+            || message.store.is_some()
+            || message.background.is_some()
+            || message.layer.is_some()
+            || message.alpha.is_some()
+            || message.mask.is_some()
+            || message.query.is_some()
+        {
+            let color = message.rgbw.map(Color::Rgbw).or_else(|| {
+                let saturation = message.saturation.unwrap_or(u8::MAX);
+                message.hue.map(|h| Color::Hsv(h, saturation, u8::MAX))
+            });
+            let letter = message.color.and_then(Mode::from_letter);
+            // Without a mode letter, an explicit color goes to the effect if there is one.
+            let effect_color = color.filter(|_| message.color.is_none());
+            let mode = match (letter, color) {
+                (Some(mode), Some(color)) => Some(mode.with_color(color)),
+                (None, Some(color)) if message.effect.is_none() => Some(Mode::Constant(color)),
+                (mode, _) => mode,
+            };
+            let mode = message.pattern.map(Mode::Pattern).or(mode);
+            let easing = message.easing.and_then(Easing::from_index);
+            let mode = match easing {
+                Some(easing) => mode.map(|m| m.with_easing(easing)),
+                None => mode,
+            };
+            let period = message.period.and_then(checked_ms);
+            let duration = message.duration.and_then(checked_ms);
+            let mode = match period {
+                Some(period) => mode.map(|m| m.with_period(period)),
+                None => mode,
+            };
+            let expiry = message.timeout.map(|t| u32::from(t).secs());
+            let effect_letter = message.effect.and_then(Mode::from_letter);
+            let effect = effect_letter.map(|effect| {
+                let effect = match effect_color {
+                    Some(color) => effect.with_color(color),
+                    None => effect,
+                };
+                let effect = match easing {
+                    Some(easing) => effect.with_easing(easing),
+                    None => effect,
+                };
+                let effect = match period {
+                    Some(period) => effect.with_period(period),
+                    None => effect,
+                };
+                (effect, message.repeat.unwrap_or(1), duration)
+            });
             let intensity = message.intensity.and_then(|i| Intensity::new(i + 1));
+            let keyframe: Option<Upload> = message.keyframe.as_deref().and_then(|k| k.parse().ok());
+            // The off letter removes the background layer instead of painting it black.
+            let background = message
+                .background
+                .and_then(Mode::from_letter)
+                .map(|mode| (mode != Mode::Constant(Color::Off)).then_some(mode));
+            let blend = message.layer.and_then(Layer::from_index).map(|layer| {
+                let blend = Blend {
+                    alpha: message.alpha.unwrap_or(Blend::OPAQUE.alpha),
+                    mask: message.mask.unwrap_or(Blend::OPAQUE.mask),
+                };
+                (layer, blend)
+            });
+            // Queries are only answered when addressed to this node alone.
+            let query = message
+                .query
+                .and_then(Query::from_index)
+                .filter(|_| message.destination == Some(DEVICE_ADDRESS));
+            let usable = mode.is_some()
+                || effect.is_some()
+                || intensity.is_some()
+                || keyframe.is_some()
+                || message.store.is_some()
+                || background.is_some()
+                || blend.is_some()
+                || query.is_some();
+            // Every field that is present must make sense and have something to act on,
+            // otherwise nothing is applied.
+            let rejected = !usable
+                || (message.hue.is_some() && message.rgbw.is_some())
+                || (message.saturation.is_some() && message.hue.is_none())
+                || ((message.easing.is_some() || message.period.is_some())
+                    && mode.is_none()
+                    && effect.is_none())
+                || (message.timeout.is_some() && mode.is_none())
+                || ((message.duration.is_some() || message.repeat.is_some()) && effect.is_none())
+                || ((message.alpha.is_some() || message.mask.is_some()) && message.layer.is_none())
+                || (message.color.is_some() && letter.is_none())
+                || (message.easing.is_some() && easing.is_none())
+                || (message.period.is_some() && period.is_none())
+                || (message.duration.is_some() && duration.is_none())
+                || (message.effect.is_some() && effect_letter.is_none())
+                || (message.intensity.is_some() && intensity.is_none())
+                || (message.keyframe.is_some() && keyframe.is_none())
+                || (message.background.is_some() && background.is_none())
+                || (message.layer.is_some() && blend.is_none())
+                || message
+                    .query
+                    .is_some_and(|q| Query::from_index(q).is_none());
 
             Some(Command {
+                background,
                 mode,
+                expiry,
                 effect,
                 intensity,
+                keyframe,
+                store: message.store,
+                blend,
+                sequence: message.sequence,
+                rejected,
+                query,
             })
         } else {
             None
@@ -76,9 +375,109 @@ impl Command {
 
     pub fn no_connection() -> Self {
         Command {
-            mode: Some(Mode::Blink(Color::Red, 3.secs())),
-            effect: Some(Mode::Glow(Color::Blue, fast_glow())),
+            background: None,
+            mode: Some(Mode::Blink(Color::Red, SLOW_BLINK)),
+            expiry: None,
+            effect: Some((Mode::Glow(Color::Blue, FAST_GLOW, Easing::Linear), 1, None)),
             intensity: None,
+            keyframe: None,
+            store: None,
+            blend: None,
+            sequence: None,
+            rejected: false,
+            query: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::Address;
+
+    fn red() -> Message {
+        Message {
+            color: Some('R'),
+            ..Default::default()
+        }
+    }
+
+    fn rejected(message: Message) -> bool {
+        Command::from_rs485(message).is_some_and(|c| c.rejected)
+    }
+
+    #[test]
+    fn routing() {
+        let other = Address::new(0xc);
+        let foreign_group = (0..=u8::MAX).find(|g| !DEVICE_GROUPS.contains(g));
+        let to = |destination, group| {
+            Command::from_rs485(Message {
+                destination,
+                group,
+                ..red()
+            })
+            .is_some()
+        };
+        assert!(to(Some(DEVICE_ADDRESS), None));
+        assert!(!to(Some(other), None));
+        assert!(!to(Some(other), Some(DEVICE_GROUPS[0])));
+        assert!(to(None, Some(DEVICE_GROUPS[0])));
+        assert!(!to(None, foreign_group));
+        assert!(to(None, None));
+    }
+
+    #[test]
+    fn period_bounds() {
+        assert_eq!(checked_ms(19), None);
+        assert_eq!(checked_ms(20), Some(ms(20)));
+        assert_eq!(checked_ms(3_600_000), Some(ms(3_600_000)));
+        assert_eq!(checked_ms(3_600_001), None);
+    }
+
+    #[test]
+    fn bad_letter_rejects_everything() {
+        assert!(!rejected(red()));
+        assert!(rejected(Message {
+            effect: Some('#'),
+            intensity: Some(3),
+            ..red()
+        }));
+        assert!(rejected(Message {
+            color: Some('#'),
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn query_only_unicast() {
+        let query = |destination, group| {
+            Command::from_rs485(Message {
+                destination,
+                group,
+                query: Some(1),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let unicast = query(Some(DEVICE_ADDRESS), None);
+        assert!(!unicast.rejected);
+        assert_eq!(unicast.query(), Some(Query::Status));
+        for command in [query(None, None), query(None, Some(DEVICE_GROUPS[0]))] {
+            assert!(command.rejected);
+            assert_eq!(command.query(), None);
         }
     }
+
+    #[test]
+    fn unused_fields_reject() {
+        assert!(rejected(Message {
+            timeout: Some(30),
+            intensity: Some(3),
+            ..Default::default()
+        }));
+        assert!(rejected(Message {
+            alpha: Some(128),
+            ..red()
+        }));
+    }
 }
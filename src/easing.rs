@@ -0,0 +1,79 @@
+//! Easing curves for glow animations. Fixed point only, the MCU has no FPU.
+
+const ONE: u32 = 256;
+
+/// `(1 - cos(πx)) / 2` for x in 0..=1, in 64 steps.
+const SINE: [u16; 65] = [
+    0, 0, 1, 1, 2, 4, 6, 7, 10, 12, 15, 18, 22, 25, 29, 33, 37, 42, 47, 52, 57, 62, 68, 73, 79, 85,
+    91, 97, 103, 109, 115, 122, 128, 134, 141, 147, 153, 159, 165, 171, 177, 183, 188, 194, 199,
+    204, 209, 214, 219, 223, 227, 231, 234, 238, 241, 244, 246, 249, 250, 252, 254, 255, 255, 256,
+    256,
+];
+
+/// `(exp(sin(π(x - 1/2))) - 1/e) / (e - 1/e)` for x in 0..=1, in 64 steps.
+const BREATHING: [u16; 65] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 4, 5, 6, 7, 9, 10, 12, 14, 16, 18, 20, 22, 25, 28, 31, 34, 38, 41,
+    45, 50, 54, 59, 64, 69, 74, 80, 86, 92, 99, 106, 112, 120, 127, 134, 142, 150, 158, 165, 173,
+    181, 188, 196, 203, 210, 217, 223, 229, 234, 239, 244, 247, 250, 253, 255, 256, 256,
+];
+
+fn lookup(table: &[u16; 65], x: u32) -> u32 {
+    let (index, fraction) = ((x / 4) as usize, x % 4);
+    let low = table[index] as u32;
+    let high = table[(index + 1).min(table.len() - 1)] as u32;
+    low + (high - low) * fraction / 4
+}
+
+fn quadratic(x: u32) -> u32 {
+    if x < ONE / 2 {
+        2 * x * x / ONE
+    } else {
+        ONE - 2 * (ONE - x) * (ONE - x) / ONE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Easing {
+    /// Triangle ramp up and down.
+    Linear,
+    Sine,
+    Quadratic,
+    /// Exponential breathing, long dim phase and a short bright peak.
+    Breathing,
+    SawUp,
+    SawDown,
+}
+
+impl Easing {
+    pub const fn from_index(index: u8) -> Option<Self> {
+        use Easing::*;
+        match index {
+            0 => Some(Linear),
+            1 => Some(Sine),
+            2 => Some(Quadratic),
+            3 => Some(Breathing),
+            4 => Some(SawUp),
+            5 => Some(SawDown),
+            _ => None,
+        }
+    }
+
+    /// Brightness at the given tick of the period, as `(numerator, denominator)`.
+    pub fn level(self, tick: u32, period: u32) -> (u32, u32) {
+        use Easing::*;
+        let x = (tick * ONE / period).min(ONE);
+        let triangle = if x <= ONE / 2 { 2 * x } else { 2 * (ONE - x) };
+        match self {
+            Linear => {
+                let max = period / 2;
+                let f = if tick <= max { tick } else { period - tick };
+                (f, max)
+            }
+            Sine => (lookup(&SINE, triangle), ONE),
+            Quadratic => (quadratic(triangle), ONE),
+            Breathing => (lookup(&BREATHING, triangle), ONE),
+            SawUp => (x, ONE),
+            SawDown => (ONE - x, ONE),
+        }
+    }
+}
@@ -1,14 +1,25 @@
 use smart_leds::{SmartLedsWrite, White as W, RGBW};
-use ws2812_uart;
 
-use crate::hal::{self, serial, stm32};
+use crate::animation::{Animation, Sequence, Upload};
+use crate::easing::Easing;
+use crate::patterns::Library;
+#[cfg(feature = "firmware")]
+use crate::{DERATE_HYSTERESIS, DERATE_STEP, DERATE_TEMPERATURE, VDDA_MIN_MV, VDDA_SAG_MV};
+use crate::{LED_BUDGET_MA, LED_CHANNEL_MA, LED_COUNT};
 
 use bounded_integer::BoundedU8;
 use fugit::Duration;
+use heapless::Deque;
 
 pub type Intensity = BoundedU8<1, 10>;
 
-#[derive(Debug, Clone, Copy)]
+const EFFECT_QUEUE: usize = 4;
+
+/// The full self-test lights one pixel at a time, the short one all pixels at once.
+const SELF_TEST_PER_PIXEL: bool = cfg!(feature = "full-self-test");
+const SELF_TEST_STEP: u32 = 25; // ticks per channel and pixel
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Color {
     Off,
     Red,
@@ -18,9 +29,51 @@ pub enum Color {
     Magenta,
     Yellow,
     White,
+    /// Arbitrary color packed as `0xRRGGBBWW`, in perceptual (pre-gamma) units.
+    Rgbw(u32),
+    /// Hue, saturation and value, each over the full `u8` range.
+    Hsv(u8, u8, u8),
 }
 
 type RawColor = RGBW<u8>;
+pub(crate) type Frame = [RawColor; LED_COUNT];
+
+/// Color as sent to the LED chain.
+#[cfg(not(feature = "ws2812b"))]
+pub type Pixel = RGBW<u8>;
+#[cfg(feature = "ws2812b")]
+pub type Pixel = smart_leds::RGB8;
+
+/// LED channels lit by each of R, G, B and W; RGB-only chains show W on all three.
+#[cfg(not(feature = "ws2812b"))]
+const CHANNEL_WEIGHTS: [u32; 4] = [1, 1, 1, 1];
+#[cfg(feature = "ws2812b")]
+const CHANNEL_WEIGHTS: [u32; 4] = [1, 1, 1, 3];
+
+#[cfg(not(feature = "ws2812b"))]
+fn to_device(color: RawColor) -> Pixel {
+    color
+}
+
+/// RGB-only chains show white as equal parts of red, green and blue.
+#[cfg(feature = "ws2812b")]
+fn to_device(color: RawColor) -> Pixel {
+    let RGBW { r, g, b, a: W(w) } = color;
+    Pixel {
+        r: r.saturating_add(w),
+        g: g.saturating_add(w),
+        b: b.saturating_add(w),
+    }
+}
+
+#[cfg(feature = "firmware")]
+impl Color {
+    /// The color packed as `0xRRGGBBWW`, like `Color::Rgbw`.
+    pub fn rgbw(self) -> u32 {
+        let RGBW { r, g, b, a: W(w) } = self.into();
+        u32::from_be_bytes([r, g, b, w])
+    }
+}
 
 impl From<Color> for RawColor {
     fn from(color: Color) -> Self {
@@ -52,19 +105,19 @@ impl From<Color> for RawColor {
             },
             Cyan => RGBW {
                 r: 0,
-                g: 128,
-                b: 128,
+                g: 186,
+                b: 186,
                 a: W(0),
             },
             Magenta => RGBW {
-                r: 138,
+                r: 193,
                 g: 0,
-                b: 90,
+                b: 159,
                 a: W(0),
             },
             Yellow => RGBW {
-                r: 150,
-                g: 90,
+                r: 200,
+                g: 159,
                 b: 0,
                 a: W(0),
             },
@@ -74,10 +127,45 @@ impl From<Color> for RawColor {
                 b: 0,
                 a: W(255),
             },
+            Rgbw(value) => {
+                let [r, g, b, w] = value.to_be_bytes();
+                RGBW { r, g, b, a: W(w) }
+            }
+            Hsv(hue, saturation, value) => hsv_to_rgbw(hue, saturation, value),
         }
     }
 }
 
+/// Integer HSV conversion. The unsaturated part common to R, G and B goes to the white
+/// channel.
+fn hsv_to_rgbw(hue: u8, saturation: u8, value: u8) -> RawColor {
+    const SECTOR: u8 = 43; // 256 / 6, rounded up
+    let sector = hue / SECTOR;
+    let offset = (hue - sector * SECTOR) * 6;
+
+    let p = value.scale((u8::MAX - saturation, u8::MAX));
+    let q = value.scale((u8::MAX - saturation.scale((offset, u8::MAX)), u8::MAX));
+    let t = value.scale((
+        u8::MAX - saturation.scale((u8::MAX - offset, u8::MAX)),
+        u8::MAX,
+    ));
+
+    let (r, g, b) = match sector {
+        0 => (value, t, p),
+        1 => (q, value, p),
+        2 => (p, value, t),
+        3 => (p, q, value),
+        4 => (t, p, value),
+        _ => (value, p, q),
+    };
+    RGBW {
+        r: r - p,
+        g: g - p,
+        b: b - p,
+        a: W(p),
+    }
+}
+
 trait ScaleColor<Factor> {
     fn scale(self, factor: Factor) -> Self;
 }
@@ -94,12 +182,6 @@ impl ScaleColor<(u32, u32)> for u8 {
     }
 }
 
-impl ScaleColor<Intensity> for u8 {
-    fn scale(self, intensity: Intensity) -> Self {
-        self.scale((intensity.get(), Intensity::MAX_VALUE))
-    }
-}
-
 impl<T, Factor> ScaleColor<Factor> for RGBW<T>
 where
     T: ScaleColor<Factor>,
@@ -116,29 +198,130 @@ where
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Perceptual to linear PWM level, gamma 2.2, in 8.8 fixed point so that dim levels keep
+/// their fractional part for dithering. Used for all four channels.
+const GAMMA: [u16; 256] = [
+    0, 0, 2, 4, 7, 11, 17, 24, 32, 42, 53, 65, 78, 94, 110, 128, 148, 169, 191, 216, 241, 269, 298,
+    328, 360, 394, 430, 467, 506, 547, 589, 633, 679, 726, 776, 827, 880, 934, 991, 1049, 1109,
+    1171, 1235, 1300, 1368, 1437, 1508, 1581, 1656, 1733, 1812, 1893, 1975, 2060, 2146, 2235, 2325,
+    2417, 2512, 2608, 2706, 2806, 2908, 3013, 3119, 3227, 3337, 3450, 3564, 3680, 3798, 3919, 4041,
+    4166, 4292, 4421, 4552, 4685, 4819, 4956, 5096, 5237, 5380, 5525, 5673, 5823, 5974, 6128, 6284,
+    6442, 6603, 6765, 6930, 7097, 7266, 7437, 7610, 7786, 7963, 8143, 8325, 8509, 8696, 8885, 9075,
+    9268, 9464, 9661, 9861, 10063, 10267, 10474, 10682, 10893, 11107, 11322, 11540, 11760, 11982,
+    12207, 12433, 12663, 12894, 13128, 13363, 13602, 13842, 14085, 14330, 14578, 14827, 15080,
+    15334, 15591, 15850, 16111, 16375, 16641, 16909, 17180, 17453, 17729, 18006, 18287, 18569,
+    18854, 19141, 19431, 19723, 20017, 20314, 20613, 20915, 21218, 21525, 21833, 22144, 22458,
+    22774, 23092, 23413, 23736, 24062, 24390, 24720, 25053, 25388, 25726, 26066, 26408, 26753,
+    27101, 27451, 27803, 28158, 28515, 28875, 29237, 29602, 29969, 30338, 30710, 31085, 31462,
+    31841, 32223, 32608, 32995, 33384, 33776, 34170, 34567, 34967, 35369, 35773, 36180, 36589,
+    37001, 37416, 37833, 38252, 38674, 39099, 39526, 39956, 40388, 40823, 41260, 41700, 42142,
+    42587, 43034, 43484, 43937, 44392, 44849, 45310, 45772, 46238, 46706, 47176, 47649, 48125,
+    48603, 49084, 49567, 50053, 50542, 51033, 51526, 52023, 52522, 53023, 53527, 54034, 54543,
+    55055, 55570, 56087, 56607, 57129, 57654, 58182, 58712, 59245, 59780, 60318, 60859, 61402,
+    61948, 62497, 63048, 63602, 64159, 64718, 65280,
+];
+
+const FULL_LEVEL: u32 = 255 << 8;
+
+/// Keeps two fractional bits for dithering, so a dim level repeats within four ticks
+/// instead of flashing now and then. Anything below a quarter step is off.
+const DITHER_MASK: u16 = 0xffc0;
+
+/// Brightness ceiling in 1/256 for each thermal derating step.
+const THERMAL_CEILING: [u32; 5] = [256, 192, 128, 96, 64];
+
+/// Linear 8.8 fixed point level for a perceptual channel value at the given intensity.
+fn linear_level(value: u8, intensity: Intensity) -> u16 {
+    let perceptual = value as u32 * 256 * intensity.get() as u32 / Intensity::MAX_VALUE as u32;
+    let (index, fraction) = ((perceptual >> 8) as usize, perceptual & 0xff);
+    let low = GAMMA[index] as u32;
+    let high = GAMMA[(index + 1).min(GAMMA.len() - 1)] as u32;
+    (low + (high - low) * fraction / 256) as u16
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Mode {
     Constant(Color),
     Blink(Color, Duration<u32, 1, 100>),
-    Glow(Color, Duration<u32, 1, 100>),
+    /// Pulses of the given on-time, separated by gaps of the same length, starting every
+    /// period: one pulse is a blink with a custom duty cycle, two a double blink.
+    Pulse(Color, Duration<u32, 1, 100>, Duration<u32, 1, 100>, u8),
+    Glow(Color, Duration<u32, 1, 100>, Easing),
+    /// A segment running around the ring once per period, with a tail fading out over
+    /// the given number of pixels.
+    Chase(Color, Duration<u32, 1, 100>, u8),
+    /// Full hue wheel once per period. When spread, the pixels show the whole wheel at
+    /// once, otherwise all pixels share one hue.
+    Rainbow(Duration<u32, 1, 100>, bool),
+    /// The keyframe animation uploaded by the master.
+    Custom,
+    /// An animation from the pattern library.
+    Pattern(u8),
+    /// Steps every channel through the pixels at full level, so that a missing color is
+    /// easy to spot.
+    SelfTest,
 }
 
 impl Mode {
+    pub const fn with_color(self, color: Color) -> Self {
+        use Mode::*;
+        match self {
+            Constant(_) => Constant(color),
+            Blink(_, period) => Blink(color, period),
+            Pulse(_, period, on, count) => Pulse(color, period, on, count),
+            Glow(_, period, easing) => Glow(color, period, easing),
+            Chase(_, period, width) => Chase(color, period, width),
+            Rainbow(..) | Custom | Pattern(_) | SelfTest => self,
+        }
+    }
+
+    pub const fn with_period(self, period: Duration<u32, 1, 100>) -> Self {
+        use Mode::*;
+        match self {
+            Blink(color, _) => Blink(color, period),
+            Pulse(color, _, on, count) => Pulse(color, period, on, count),
+            Glow(color, _, easing) => Glow(color, period, easing),
+            Chase(color, _, width) => Chase(color, period, width),
+            Rainbow(_, spread) => Rainbow(period, spread),
+            Constant(_) | Custom | Pattern(_) | SelfTest => self,
+        }
+    }
+
+    pub const fn with_easing(self, easing: Easing) -> Self {
+        match self {
+            Mode::Glow(color, period, _) => Mode::Glow(color, period, easing),
+            _ => self,
+        }
+    }
+
     const fn max_ticks(&self) -> u32 {
         use Mode::*;
         match self {
             Constant(_) => 1000,
             Blink(_, period) => period.ticks(),
-            Glow(_, period) => period.ticks(),
+            Pulse(_, period, _, _) => period.ticks(),
+            Glow(_, period, _) => period.ticks(),
+            Chase(_, period, _) => period.ticks(),
+            Rainbow(period, _) => period.ticks(),
+            Custom | Pattern(_) => u32::MAX, // loops or holds by itself
+            SelfTest => {
+                let pixels = if SELF_TEST_PER_PIXEL { LED_COUNT } else { 1 };
+                4 * pixels as u32 * SELF_TEST_STEP
+            }
         }
     }
 
-    fn color_for_tick(&self, tick: u32, on_reset: bool) -> Option<RawColor> {
+    fn frame_for_tick(
+        &self,
+        tick: u32,
+        on_reset: bool,
+        sequence: Option<Sequence<'_>>,
+    ) -> Option<Frame> {
         use Mode::*;
         match self {
             Constant(color) => {
                 if tick == 0 || on_reset {
-                    Some((*color).into())
+                    Some([(*color).into(); LED_COUNT])
                 } else {
                     None
                 }
@@ -150,64 +333,356 @@ impl Mode {
                     *color
                 };
                 if tick == 0 || tick == period.ticks() / 2 || on_reset {
-                    Some(c.into())
+                    Some([c.into(); LED_COUNT])
                 } else {
                     None
                 }
             }
-            Glow(color, period) => {
-                let max = period.ticks() / 2;
-                let f = if tick <= max {
-                    tick
-                } else {
-                    period.ticks() - tick
+            Pulse(color, _, on, count) => {
+                let lit = |tick: u32| {
+                    let on = on.ticks().max(1);
+                    tick / (2 * on) < *count as u32 && tick % (2 * on) < on
                 };
-                Some(RawColor::from(*color).scale((f, max)))
+                if tick == 0 || on_reset || lit(tick) != lit(tick - 1) {
+                    let c = if lit(tick) { *color } else { Color::Off };
+                    Some([c.into(); LED_COUNT])
+                } else {
+                    None
+                }
+            }
+            Glow(color, period, easing) => {
+                let level = easing.level(tick, period.ticks());
+                Some([RawColor::from(*color).scale(level); LED_COUNT])
+            }
+            Chase(color, period, width) => {
+                const STEP: u32 = 256; // sub-pixel positions
+                let ring = LED_COUNT as u32 * STEP;
+                let head = tick * ring / period.ticks();
+                let tail = u32::from(*width).max(1) * STEP;
+                let color = RawColor::from(*color);
+                let mut frame: Frame = [Color::Off.into(); LED_COUNT];
+                for (n, pixel) in frame.iter_mut().enumerate() {
+                    let distance = (head + ring - n as u32 * STEP) % ring;
+                    if distance < tail {
+                        *pixel = color.scale((tail - distance, tail));
+                    }
+                }
+                Some(frame)
+            }
+            Rainbow(period, spread) => {
+                let hue = tick * 256 / period.ticks();
+                let mut frame: Frame = [Color::Off.into(); LED_COUNT];
+                for (n, pixel) in frame.iter_mut().enumerate() {
+                    let shift = if *spread {
+                        n as u32 * 256 / LED_COUNT as u32
+                    } else {
+                        0
+                    };
+                    let hue = ((hue + shift) % 256) as u8;
+                    *pixel = hsv_to_rgbw(hue, u8::MAX, u8::MAX);
+                }
+                Some(frame)
+            }
+            Custom | Pattern(_) => Some(
+                sequence
+                    .map(|s| s.frame_for_tick(tick))
+                    .unwrap_or([Color::Off.into(); LED_COUNT]),
+            ),
+            SelfTest => {
+                if tick.is_multiple_of(SELF_TEST_STEP) || on_reset {
+                    let step = tick / SELF_TEST_STEP;
+                    let (channel, pixel) = if SELF_TEST_PER_PIXEL {
+                        let pixels = LED_COUNT as u32;
+                        (step / pixels, Some((step % pixels) as usize))
+                    } else {
+                        (step, None)
+                    };
+                    let mut channels = [0_u8; 4];
+                    channels[channel as usize % 4] = u8::MAX;
+                    let [r, g, b, w] = channels;
+                    let mut frame: Frame = [Color::Off.into(); LED_COUNT];
+                    for (n, px) in frame.iter_mut().enumerate() {
+                        if pixel.is_none_or(|p| p == n) {
+                            *px = RGBW { r, g, b, a: W(w) };
+                        }
+                    }
+                    Some(frame)
+                } else {
+                    None
+                }
             }
         }
     }
 }
 
-pub struct Leds {
-    led: ws2812_uart::Ws2812<
-        hal::serial::Serial<stm32::USART2, serial::BasicConfig>,
-        ws2812_uart::device::Sk6812w,
-    >,
-    dirty: bool,
+/// What the master sees of the mode in a status reply.
+#[cfg(feature = "firmware")]
+impl Mode {
+    /// Position of the variant in `Mode`.
+    pub const fn index(&self) -> u8 {
+        use Mode::*;
+        match self {
+            Constant(_) => 0,
+            Blink(..) => 1,
+            Pulse(..) => 2,
+            Glow(..) => 3,
+            Chase(..) => 4,
+            Rainbow(..) => 5,
+            Custom => 6,
+            Pattern(_) => 7,
+            SelfTest => 8,
+        }
+    }
+
+    pub const fn color(&self) -> Option<Color> {
+        use Mode::*;
+        match self {
+            Constant(color)
+            | Blink(color, _)
+            | Pulse(color, ..)
+            | Glow(color, ..)
+            | Chase(color, ..) => Some(*color),
+            Rainbow(..) | Custom | Pattern(_) | SelfTest => None,
+        }
+    }
+
+    pub const fn period(&self) -> Option<Duration<u32, 1, 100>> {
+        use Mode::*;
+        match self {
+            Blink(_, period)
+            | Pulse(_, period, ..)
+            | Glow(_, period, _)
+            | Chase(_, period, _)
+            | Rainbow(period, _) => Some(*period),
+            Constant(_) | Custom | Pattern(_) | SelfTest => None,
+        }
+    }
+
+    pub const fn pattern(&self) -> Option<u8> {
+        match self {
+            Mode::Pattern(index) => Some(*index),
+            _ => None,
+        }
+    }
+}
+
+fn mix(from: u8, to: u8, (step, steps): (u32, u32)) -> u8 {
+    from.scale((steps - step, steps)) + to.scale((step, steps))
+}
+
+pub(crate) fn blend(from: &Frame, to: &Frame, amount: (u32, u32)) -> Frame {
+    let mut frame = *to;
+    for (pixel, from) in frame.iter_mut().zip(from.iter()) {
+        *pixel = RGBW {
+            r: mix(from.r, pixel.r, amount),
+            g: mix(from.g, pixel.g, amount),
+            b: mix(from.b, pixel.b, amount),
+            a: W(mix(from.a.0, pixel.a.0, amount)),
+        };
+    }
+    frame
+}
+
+struct Crossfade {
+    from: Frame,
+    step: u32,
+    steps: u32,
+}
+
+impl Crossfade {
+    fn blend(&self, to: &Frame) -> Frame {
+        blend(&self.from, to, (self.step, self.steps))
+    }
+}
+
+struct Effect {
     mode: Mode,
-    effect: Option<Mode>,
+    ticks: u32,
+    plays_left: u8,
+}
+
+struct Revert {
+    previous: Mode,
+    ticks_left: u32,
+}
+
+/// Layers in priority order; higher layers are composited on top of lower ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    /// Optional mode under everything else, e.g. a nameplate backlight.
+    Background = 0,
+    /// The base mode.
+    Status = 1,
+    /// The effect queue, only present while an effect is playing.
+    Alert = 2,
+}
+
+const LAYERS: [Layer; 3] = [Layer::Background, Layer::Status, Layer::Alert];
+
+impl Layer {
+    pub const fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Self::Background),
+            1 => Some(Self::Status),
+            2 => Some(Self::Alert),
+            _ => None,
+        }
+    }
+}
+
+/// How a layer covers the layers below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Blend {
+    /// Opacity; 255 hides the layers below completely.
+    pub alpha: u8,
+    /// Bit `n` set when the layer covers pixel `n`.
+    pub mask: u32,
+}
+
+impl Blend {
+    pub const OPAQUE: Self = Self {
+        alpha: u8::MAX,
+        mask: u32::MAX,
+    };
+
+    fn apply(&self, below: &Frame, above: &Frame) -> Frame {
+        let mixed = blend(below, above, (self.alpha as u32, u8::MAX as u32));
+        let mut frame = *below;
+        for (n, (pixel, mixed)) in frame.iter_mut().zip(mixed).enumerate() {
+            if n < 32 && self.mask & (1 << n) != 0 {
+                *pixel = mixed;
+            }
+        }
+        frame
+    }
+}
+
+struct LayerState {
+    blend: Blend,
     tick: u32,
-    intensity: Intensity,
+    frame: Frame,
 }
 
-impl Leds {
-    pub fn new(
-        uart: hal::serial::Serial<stm32g0xx_hal::stm32::USART2, serial::BasicConfig>,
-    ) -> Self {
-        let led = ws2812_uart::Ws2812::<_, ws2812_uart::device::Sk6812w>::new(uart);
+impl LayerState {
+    fn new() -> Self {
+        Self {
+            blend: Blend::OPAQUE,
+            tick: 0,
+            frame: [Color::Off.into(); LED_COUNT],
+        }
+    }
+}
+
+fn sequence<'a>(
+    mode: &Mode,
+    animation: &'a Animation,
+    library: &'a Library,
+) -> Option<Sequence<'a>> {
+    match mode {
+        Mode::Custom => Some(animation.sequence()),
+        Mode::Pattern(index) => library.pattern(*index),
+        _ => None,
+    }
+}
+
+pub struct Leds<L> {
+    led: L,
+    frame: Frame,
+    output: Frame,
+    dither: [[u8; 4]; LED_COUNT],
+    rewrite: bool,
+    budget: u32,
+    thermal_step: usize,
+    fade: Duration<u32, 1, 100>,
+    crossfade: Option<Crossfade>,
+    dirty: bool,
+    background: Option<Mode>,
+    mode: Mode,
+    revert: Option<Revert>,
+    effects: Deque<Effect, EFFECT_QUEUE>,
+    animation: Animation,
+    library: Library,
+    layers: [LayerState; LAYERS.len()],
+    intensity: Intensity,
+}
 
+impl<L: SmartLedsWrite<Color = Pixel>> Leds<L> {
+    pub fn new(led: L, library: Library) -> Self {
         let mode = Mode::Constant(Color::Magenta);
         Self {
             led,
+            frame: [Color::Off.into(); LED_COUNT],
+            output: [Color::Off.into(); LED_COUNT],
+            dither: [[0; 4]; LED_COUNT],
+            rewrite: false,
+            budget: LED_BUDGET_MA,
+            thermal_step: 0,
+            fade: Duration::<u32, 1, 100>::from_ticks(0),
+            crossfade: None,
+            background: None,
             mode,
-            tick: 0,
+            revert: None,
+            layers: [LayerState::new(), LayerState::new(), LayerState::new()],
             intensity: Intensity::MAX,
-            effect: None,
+            effects: Deque::new(),
+            animation: Animation::new(),
+            library,
             dirty: true,
         }
     }
 
+    #[cfg(feature = "firmware")]
     pub const fn period(&self) -> Duration<u64, 1, 1000> {
         Duration::<u64, 1, 1000>::from_ticks(10)
     }
 
     pub fn tick(&mut self) {
-        self.tick += 1;
+        if let Some(revert) = &mut self.revert {
+            revert.ticks_left = revert.ticks_left.saturating_sub(1);
+            if revert.ticks_left == 0 {
+                let previous = revert.previous;
+                self.set_mode(previous, None);
+            }
+        }
+        // Inactive layers restart from zero when they come back.
+        for layer in LAYERS {
+            if self.layer_mode(layer).is_some() {
+                self.layers[layer as usize].tick += 1;
+            }
+        }
         self.refresh()
     }
 
-    pub fn set_mode(&mut self, mode: Mode) {
+    /// Sets the time to blend between frames when the mode changes or an effect ends.
+    /// Zero switches instantly.
+    pub fn set_fade(&mut self, fade: Duration<u32, 1, 100>) {
+        self.fade = fade;
+    }
+
+    /// Sets the mode of the background layer, or removes the layer with `None`.
+    pub fn set_background(&mut self, mode: Option<Mode>) {
+        self.background = mode;
+        self.layers[Layer::Background as usize].tick = 0;
+        self.start_crossfade();
+        self.dirty = true;
+    }
+
+    /// Sets the base mode. With an expiry, the mode that was active before the first
+    /// timed mode in a row is restored when the time is over.
+    pub fn set_mode(&mut self, mode: Mode, expiry: Option<Duration<u32, 1, 100>>) {
+        self.revert = expiry.map(|expiry| Revert {
+            previous: self.revert.take().map(|r| r.previous).unwrap_or(self.mode),
+            ticks_left: expiry.ticks(),
+        });
         self.mode = mode;
+        self.layers[Layer::Status as usize].tick = 0;
+        self.start_crossfade();
+        self.dirty = true;
+    }
+
+    /// Sets how a layer covers the layers below it.
+    pub fn set_blend(&mut self, layer: Layer, blend: Blend) {
+        self.layers[layer as usize].blend = blend;
         self.dirty = true;
     }
 
@@ -216,32 +691,273 @@ impl Leds {
         self.dirty = true;
     }
 
-    pub fn show_effect(&mut self, effect: Mode) {
-        self.tick = 0;
-        self.effect = Some(effect);
-        self.dirty = true;
+    #[cfg(feature = "firmware")]
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    #[cfg(feature = "firmware")]
+    pub fn intensity(&self) -> Intensity {
+        self.intensity
+    }
+
+    /// Derates the LED current budget when VDDA shows the supply sagging. Zero means
+    /// no reading yet.
+    #[cfg(feature = "firmware")]
+    pub fn set_supply_voltage(&mut self, millivolts: u16) {
+        let millivolts = millivolts as u32;
+        let budget = if millivolts == 0 || millivolts >= VDDA_SAG_MV {
+            LED_BUDGET_MA
+        } else {
+            LED_BUDGET_MA * millivolts.saturating_sub(VDDA_MIN_MV) / (VDDA_SAG_MV - VDDA_MIN_MV)
+        };
+        if budget != self.budget {
+            self.budget = budget;
+            self.rewrite = true;
+        }
+    }
+
+    /// Moves the brightness ceiling by at most one step per call: down when the chip
+    /// temperature (in 0.1 °C) reaches the next threshold, back up when it falls below the
+    /// previous one by the hysteresis.
+    #[cfg(feature = "firmware")]
+    pub fn set_temperature(&mut self, temperature: i16) {
+        let step = self.thermal_step as i16;
+        let up = DERATE_TEMPERATURE + step * DERATE_STEP;
+        let down = up - DERATE_STEP - DERATE_HYSTERESIS;
+        if self.thermal_step + 1 < THERMAL_CEILING.len() && temperature >= up {
+            self.thermal_step += 1;
+            self.rewrite = true;
+        } else if self.thermal_step > 0 && temperature < down {
+            self.thermal_step -= 1;
+            self.rewrite = true;
+        }
+    }
+
+    #[cfg(feature = "firmware")]
+    pub fn is_derating(&self) -> bool {
+        self.thermal_step > 0
+    }
+
+    #[cfg(feature = "firmware")]
+    pub fn is_self_testing(&self) -> bool {
+        self.effects
+            .iter()
+            .any(|e| matches!(e.mode, Mode::SelfTest))
+    }
+
+    /// Whether another effect fits into the queue.
+    pub fn can_show_effect(&self) -> bool {
+        !self.effects.is_full()
+    }
+
+    /// Queues an effect on the alert layer to be played `repeat` times after the ones
+    /// already queued. Without a duration, one play is one period of the mode; with
+    /// one, the mode repeats for that long. Returns `false` if the queue is full.
+    pub fn show_effect(
+        &mut self,
+        effect: Mode,
+        repeat: u8,
+        duration: Option<Duration<u32, 1, 100>>,
+    ) -> bool {
+        if self.effects.is_full() {
+            return false;
+        }
+        if self.effects.is_empty() {
+            // Effects start at once, without blending into a running crossfade.
+            self.crossfade = None;
+            self.layers[Layer::Alert as usize].tick = 0;
+            self.dirty = true;
+        }
+        let ticks = match (duration, effect) {
+            (Some(duration), _) => duration.ticks(),
+            (None, Mode::Custom | Mode::Pattern(_)) => {
+                sequence(&effect, &self.animation, &self.library).map_or(0, |s| s.ticks())
+            }
+            (None, _) => effect.max_ticks(),
+        };
+        self.effects
+            .push_back(Effect {
+                mode: effect,
+                ticks,
+                plays_left: repeat.max(1),
+            })
+            .is_ok()
+    }
+
+    pub fn accepts_keyframe(&self, upload: &Upload) -> bool {
+        self.animation.accepts(upload)
+    }
+
+    /// Adds a keyframe to the custom animation. Returns `false` if it was rejected.
+    pub fn load_keyframe(&mut self, upload: Upload) -> bool {
+        self.animation.load(upload)
+    }
+
+    /// Whether the mode has something to show: the custom animation must be uploaded
+    /// and a library pattern installed.
+    pub fn can_play(&self, mode: Mode) -> bool {
+        match mode {
+            Mode::Custom => !self.animation.is_empty(),
+            Mode::Pattern(index) => self.library.pattern(index).is_some(),
+            _ => true,
+        }
+    }
+
+    pub fn has_animation(&self) -> bool {
+        !self.animation.is_empty()
+    }
+
+    pub fn can_store_pattern(&self, index: u8) -> bool {
+        self.library.can_store(index)
+    }
+
+    /// Installs the custom animation into the pattern library. See `Library::store`
+    /// for what this does to the bus.
+    pub fn store_pattern(&mut self, index: u8) -> bool {
+        self.library.store(index, &self.animation)
+    }
+
+    fn layer_mode(&self, layer: Layer) -> Option<Mode> {
+        match layer {
+            Layer::Background => self.background,
+            Layer::Status => Some(self.mode),
+            Layer::Alert => self.effects.front().map(|e| e.mode),
+        }
     }
 
     fn refresh(&mut self) {
-        let max = self
-            .effect
-            .map(|e| e.max_ticks())
-            .unwrap_or(self.mode.max_ticks());
-        if self.tick >= max {
-            self.tick = 0;
-            self.effect = None;
+        let alert = &mut self.layers[Layer::Alert as usize];
+        if let Some(effect) = self.effects.front_mut() {
+            if alert.tick >= effect.ticks {
+                alert.tick = 0;
+                effect.plays_left -= 1;
+                if effect.plays_left == 0 {
+                    self.effects.pop_front();
+                    if self.effects.is_empty() {
+                        self.start_crossfade();
+                    }
+                    self.dirty = true;
+                }
+            }
+        }
+
+        let mut changed = false;
+        for layer in LAYERS {
+            let mode = match self.layer_mode(layer) {
+                Some(mode) => mode,
+                None => continue,
+            };
+            let sequence = sequence(&mode, &self.animation, &self.library);
+            let state = &mut self.layers[layer as usize];
+            if layer != Layer::Alert && state.tick >= mode.max_ticks() {
+                state.tick = 0;
+            }
+            // Effects may last longer than one period of their mode.
+            let tick = state.tick % mode.max_ticks();
+            if let Some(frame) = mode.frame_for_tick(tick, self.dirty, sequence) {
+                state.frame = frame;
+                changed = true;
+            }
+        }
+        let rendered = if changed {
+            self.frame = self.compose();
+            Some(self.frame)
+        } else {
+            None
         };
-        let mode = self.effect.as_ref().unwrap_or(&self.mode);
-        if let Some(color) = mode.color_for_tick(self.tick, self.dirty) {
-            self.set_board_color_raw(color);
+
+        let output = match &mut self.crossfade {
+            Some(crossfade) => {
+                crossfade.step += 1;
+                let frame = crossfade.blend(&self.frame);
+                if crossfade.step >= crossfade.steps {
+                    self.crossfade = None;
+                }
+                Some(frame)
+            }
+            None => rendered,
+        };
+        if let Some(frame) = output {
+            self.output = frame;
+            self.write_frame();
+        } else if self.rewrite {
+            self.write_frame();
         }
         self.dirty = false;
     }
 
-    fn set_board_color_raw(&mut self, color: RawColor) {
-        let color = color.scale(self.intensity);
-        self.led
-            .write([color; 8].into_iter())
-            .expect("Error sending LED color");
+    /// Stacks the active layers from the lowest priority up.
+    fn compose(&self) -> Frame {
+        let mut frame: Frame = [Color::Off.into(); LED_COUNT];
+        for layer in LAYERS {
+            if self.layer_mode(layer).is_some() {
+                let state = &self.layers[layer as usize];
+                frame = state.blend.apply(&frame, &state.frame);
+            }
+        }
+        frame
+    }
+
+    fn start_crossfade(&mut self) {
+        let steps = self.fade.ticks();
+        if steps > 0 {
+            self.crossfade = Some(Crossfade {
+                from: self.output,
+                step: 0,
+                steps,
+            });
+        }
+    }
+
+    /// Converts the output frame to linear levels, limits them to the thermal ceiling
+    /// and the current budget and writes them. Fractional parts are carried over to the
+    /// next frames, so dim levels average out to the right value.
+    fn write_frame(&mut self) {
+        let intensity = self.intensity;
+        let ceiling = THERMAL_CEILING[self.thermal_step];
+        let mut levels = [[0_u16; 4]; LED_COUNT];
+        let mut total = 0;
+        for (pixel, levels) in self.output.iter().zip(levels.iter_mut()) {
+            let RGBW { r, g, b, a: W(w) } = *pixel;
+            for ((level, channel), weight) in
+                levels.iter_mut().zip([r, g, b, w]).zip(CHANNEL_WEIGHTS)
+            {
+                *level = (linear_level(channel, intensity) as u32 * ceiling / 256) as u16;
+                total += *level as u32 * weight;
+            }
+        }
+        let current = total * LED_CHANNEL_MA / FULL_LEVEL;
+        let budget = self.budget;
+
+        let mut frame: Frame = [Color::Off.into(); LED_COUNT];
+        let mut dither = self.dither;
+        let mut rewrite = false;
+        for ((pixel, levels), errors) in frame.iter_mut().zip(levels).zip(dither.iter_mut()) {
+            let mut channels = [0_u8; 4];
+            for ((channel, level), error) in channels.iter_mut().zip(levels).zip(errors.iter_mut())
+            {
+                let level = if current > budget {
+                    (level as u32 * budget / current) as u16
+                } else {
+                    level
+                } & DITHER_MASK;
+                let dithered = level + *error as u16;
+                *channel = (dithered >> 8) as u8;
+                *error = dithered as u8;
+                rewrite |= level & 0xff != 0;
+            }
+            let [r, g, b, w] = channels;
+            *pixel = RGBW { r, g, b, a: W(w) };
+        }
+        let written = self.led.write(frame.iter().map(|color| to_device(*color)));
+        // The previous transfer may still be running; then try again on the next tick,
+        // with the dithering state of this attempt thrown away.
+        if written.is_ok() {
+            self.dither = dither;
+            self.rewrite = rewrite;
+        } else {
+            self.rewrite = true;
+        }
     }
 }
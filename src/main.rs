@@ -1,15 +1,60 @@
-#![cfg_attr(not(test), no_std)]
-#![no_main]
+#![cfg_attr(all(not(test), feature = "firmware"), no_std)]
+#![cfg_attr(feature = "firmware", no_main)]
 
+#[cfg(feature = "firmware")]
 mod adc;
+mod animation;
+#[cfg(feature = "firmware")]
 mod app;
+#[cfg(feature = "firmware")]
+mod boot;
+// The host renderer only takes mode letters, not bus commands.
+#[cfg_attr(not(feature = "firmware"), allow(dead_code))]
 mod command;
+mod easing;
 mod led;
+mod patterns;
+#[cfg(not(feature = "firmware"))]
+mod render;
+#[cfg(feature = "firmware")]
 mod rs485;
+#[cfg(feature = "firmware")]
+mod ws2812;
 
+#[cfg(feature = "firmware")]
 use stm32g0xx_hal as hal;
 
+#[cfg(feature = "firmware")]
 pub(crate) const RS485_BAUD: u32 = 115200;
+#[cfg(feature = "firmware")]
 pub(crate) const MAX_DETECT_CYCLES: u32 = 8192;
+#[cfg(feature = "firmware")]
 pub(crate) const MAX_ALONE_CYCLES: u32 = 8192;
+pub(crate) const LED_COUNT: usize = match option_env!("LED_COUNT") {
+    Some(count) => match usize::from_str_radix(count, 10) {
+        Ok(count @ 1..=32) => count, // pixel masks are 32 bits wide
+        _ => panic!("LED_COUNT must be a number from 1 to 32"),
+    },
+    None => 8,
+};
+pub(crate) const LED_CHANNEL_MA: u32 = 16; // one fully lit channel
+pub(crate) const LED_BUDGET_MA: u32 = 400; // whole chain, at nominal supply
+#[cfg(feature = "firmware")]
+pub(crate) const VDDA_SAG_MV: u32 = 3200; // start derating the LED budget below this
+#[cfg(feature = "firmware")]
+pub(crate) const VDDA_MIN_MV: u32 = 3000; // LED budget is zero at this
+#[cfg(feature = "firmware")]
+pub(crate) const DERATE_TEMPERATURE: i16 = 600; // 0.1 °C, first brightness step above this
+#[cfg(feature = "firmware")]
+pub(crate) const DERATE_STEP: i16 = 50; // 0.1 °C between brightness steps
+#[cfg(feature = "firmware")]
+pub(crate) const DERATE_HYSTERESIS: i16 = 30; // 0.1 °C
+pub(crate) const MIN_PERIOD_MS: u32 = 20; // two LED ticks
+pub(crate) const MAX_PERIOD_MS: u32 = 3_600_000; // keeps the mode maths within u32
 pub(crate) const DEVICE_ADDRESS: protocol::Address = protocol::Address::new(0xb);
+pub(crate) const DEVICE_GROUPS: &[u8] = &[1]; // multicast groups this node listens to
+
+#[cfg(not(feature = "firmware"))]
+fn main() {
+    render::main()
+}
@@ -0,0 +1,296 @@
+//! Animation pattern library in the last flash page.
+//!
+//! The page holds fixed-size slots, each with one animation and its own CRC-checked
+//! header, so a slot is either complete or ignored. A free slot is programmed directly;
+//! rewriting a slot in use erases the page and programs the other slots back.
+//!
+//! Host builds keep the page in RAM instead.
+
+use crate::animation::{Animation, Keyframe, Sequence, MAX_KEYFRAMES};
+#[cfg(feature = "firmware")]
+use crate::hal::stm32::FLASH;
+use core::mem::size_of;
+use core::ptr;
+
+#[cfg(feature = "firmware")]
+const PAGE: u32 = 15;
+const PAGE_SIZE: usize = 2048;
+#[cfg(feature = "firmware")]
+const BASE: usize = 0x0800_0000 + PAGE as usize * PAGE_SIZE; // keep in sync with memory.x
+const SLOT_SIZE: usize = 256;
+pub const SLOTS: usize = PAGE_SIZE / SLOT_SIZE;
+
+const MAGIC: u16 = 0x4b50;
+const NO_LOOP: u8 = 0xff;
+
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_IBM_3740);
+
+#[cfg(not(feature = "firmware"))]
+#[repr(C, align(8))]
+struct Page([u8; PAGE_SIZE]);
+
+#[cfg(feature = "firmware")]
+mod regs {
+    pub const KEY1: u32 = 0x4567_0123;
+    pub const KEY2: u32 = 0xcdef_89ab;
+    pub const SR_BSY1: u32 = 1 << 16;
+    pub const SR_CFGBSY: u32 = 1 << 18;
+    pub const SR_ERRORS: u32 = 0xc3fa;
+    pub const CR_PG: u32 = 1 << 0;
+    pub const CR_PER: u32 = 1 << 1;
+    pub const CR_PNB_SHIFT: u32 = 3;
+    pub const CR_STRT: u32 = 1 << 16;
+    pub const CR_LOCK: u32 = 1 << 31;
+}
+#[cfg(feature = "firmware")]
+use regs::*;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Slot {
+    magic: u16,
+    count: u8,
+    loop_from: u8,
+    crc: u16,
+    _reserved: u16,
+    keyframes: [Keyframe; MAX_KEYFRAMES],
+}
+
+impl Slot {
+    fn new(animation: &Animation) -> Self {
+        let sequence = animation.sequence();
+        let mut slot = Slot {
+            magic: MAGIC,
+            count: sequence.keyframes().len() as u8,
+            loop_from: sequence.loop_from().unwrap_or(NO_LOOP),
+            crc: 0,
+            _reserved: 0xffff,
+            keyframes: [Keyframe {
+                color: 0,
+                mask: 0,
+                fade: 0,
+                hold: 0,
+            }; MAX_KEYFRAMES],
+        };
+        slot.keyframes[..sequence.keyframes().len()].copy_from_slice(sequence.keyframes());
+        slot.crc = slot.checksum();
+        slot
+    }
+
+    fn checksum(&self) -> u16 {
+        let count = (self.count as usize).min(MAX_KEYFRAMES);
+        let keyframes = unsafe {
+            core::slice::from_raw_parts(
+                self.keyframes.as_ptr() as *const u8,
+                count * size_of::<Keyframe>(),
+            )
+        };
+        let mut digest = CRC.digest();
+        digest.update(&[self.count, self.loop_from]);
+        digest.update(keyframes);
+        digest.finalize()
+    }
+}
+
+pub struct Library {
+    #[cfg(feature = "firmware")]
+    flash: FLASH,
+    #[cfg(not(feature = "firmware"))]
+    page: Page,
+}
+
+impl Library {
+    #[cfg(feature = "firmware")]
+    pub fn new(flash: FLASH) -> Self {
+        Self { flash }
+    }
+
+    #[cfg(not(feature = "firmware"))]
+    pub fn new() -> Self {
+        Self {
+            page: Page([0xff; PAGE_SIZE]),
+        }
+    }
+
+    #[cfg(feature = "firmware")]
+    fn base(&self) -> usize {
+        BASE
+    }
+
+    #[cfg(not(feature = "firmware"))]
+    fn base(&self) -> usize {
+        self.page.0.as_ptr() as usize
+    }
+
+    fn address(&self, index: u8) -> usize {
+        self.base() + index as usize * SLOT_SIZE
+    }
+
+    fn slot(&self, index: u8) -> Option<&Slot> {
+        if (index as usize) < SLOTS {
+            Some(unsafe { &*(self.address(index) as *const Slot) })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the pattern in the given slot if it is installed and intact.
+    pub fn pattern(&self, index: u8) -> Option<Sequence<'_>> {
+        let slot = self.slot(index)?;
+        if slot.magic != MAGIC || slot.count as usize > MAX_KEYFRAMES || slot.checksum() != slot.crc
+        {
+            return None;
+        }
+        let loop_from = match slot.loop_from {
+            NO_LOOP => None,
+            n => Some(n),
+        };
+        Some(Sequence::new(
+            &slot.keyframes[..slot.count as usize],
+            loop_from,
+        ))
+    }
+
+    pub fn can_store(&self, index: u8) -> bool {
+        (index as usize) < SLOTS
+    }
+
+    /// Writes the animation into the given slot. A slot in use is rewritten by erasing
+    /// the page and programming the other intact slots back from RAM; a reset in between
+    /// loses them. Returns `false` if the slot does not exist, the animation is empty or
+    /// flash programming failed.
+    ///
+    /// Erasing stalls the CPU, interrupts included, for up to 40 ms, so bytes received on
+    /// the bus in that time are lost. The master should store patterns while the bus is
+    /// otherwise quiet.
+    pub fn store(&mut self, index: u8, animation: &Animation) -> bool {
+        if !self.can_store(index) || animation.is_empty() {
+            return false;
+        }
+        let slot = Slot::new(animation);
+        let target = self.address(index);
+
+        self.unlock();
+        let ok = if Self::is_erased(target) {
+            self.program(target, &slot)
+        } else {
+            self.rewrite(index, &slot)
+        };
+        self.lock();
+        ok
+    }
+
+    fn rewrite(&mut self, index: u8, slot: &Slot) -> bool {
+        let mut slots = [None; SLOTS];
+        for (n, kept) in slots.iter_mut().enumerate() {
+            if self.pattern(n as u8).is_some() {
+                *kept = self.slot(n as u8).copied();
+            }
+        }
+        slots[index as usize] = Some(*slot);
+        self.erase_page()
+            && (0..SLOTS as u8).all(|n| match &slots[n as usize] {
+                Some(slot) => self.program(self.address(n), slot),
+                None => true,
+            })
+    }
+
+    fn is_erased(address: usize) -> bool {
+        (0..SLOT_SIZE).all(|n| unsafe { ptr::read_volatile((address + n) as *const u8) } == 0xff)
+    }
+
+    fn words(slot: &Slot) -> &[u32] {
+        unsafe {
+            core::slice::from_raw_parts(slot as *const Slot as *const u32, size_of::<Slot>() / 4)
+        }
+    }
+}
+
+#[cfg(feature = "firmware")]
+impl Library {
+    fn unlock(&mut self) {
+        if self.flash.cr.read().bits() & CR_LOCK != 0 {
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_LOCK) });
+    }
+
+    /// Waits for the previous operation and returns `true` if it succeeded.
+    fn wait(&mut self) -> bool {
+        while self.flash.sr.read().bits() & (SR_BSY1 | SR_CFGBSY) != 0 {}
+        let errors = self.flash.sr.read().bits() & SR_ERRORS;
+        self.flash.sr.write(|w| unsafe { w.bits(errors) });
+        errors == 0
+    }
+
+    fn erase_page(&mut self) -> bool {
+        if !self.wait() {
+            return false;
+        }
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_PER | (PAGE << CR_PNB_SHIFT)) });
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_STRT) });
+        let ok = self.wait();
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !(CR_PER | (0x3f << CR_PNB_SHIFT))) });
+        ok
+    }
+
+    /// Programs the slot in double words, the flash programming unit.
+    fn program(&mut self, address: usize, slot: &Slot) -> bool {
+        let words = Self::words(slot);
+        if !self.wait() {
+            return false;
+        }
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() | CR_PG) });
+        let mut ok = true;
+        for (n, pair) in words.chunks_exact(2).enumerate() {
+            let target = (address + n * 8) as *mut u32;
+            unsafe {
+                ptr::write_volatile(target, pair[0]);
+                ptr::write_volatile(target.add(1), pair[1]);
+            }
+            if !self.wait() {
+                ok = false;
+                break;
+            }
+        }
+        self.flash
+            .cr
+            .modify(|r, w| unsafe { w.bits(r.bits() & !CR_PG) });
+        ok
+    }
+}
+
+#[cfg(not(feature = "firmware"))]
+impl Library {
+    fn unlock(&mut self) {}
+
+    fn lock(&mut self) {}
+
+    fn erase_page(&mut self) -> bool {
+        self.page.0.fill(0xff);
+        true
+    }
+
+    fn program(&mut self, address: usize, slot: &Slot) -> bool {
+        let offset = address - self.base();
+        let words = Self::words(slot).iter().flat_map(|w| w.to_ne_bytes());
+        for (byte, word) in self.page.0[offset..].iter_mut().zip(words) {
+            *byte = word;
+        }
+        true
+    }
+}
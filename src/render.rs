@@ -0,0 +1,345 @@
+//! Host renderer: plays a mode, optionally with an effect on top, and prints the frame
+//! sent to the LED chain after every tick.
+//!
+//! `render <mode> [effect] [--ticks N] [--fade MS] [--ppm]`
+//!
+//! Modes and effects are given by their command letters. The output is CSV with one row
+//! per tick, or a binary PPM image with one pixel column per LED and one row per tick.
+//!
+//! The tests compare every mode with the CSV files in `golden/`. After an intended
+//! change, rerun them with `UPDATE_GOLDEN=1` and review the diff of the files.
+
+use crate::command::FromLetter;
+use crate::led::{Leds, Mode, Pixel};
+use crate::patterns::Library;
+use crate::LED_COUNT;
+use fugit::Duration;
+use smart_leds::SmartLedsWrite;
+use std::cell::Cell;
+use std::convert::Infallible;
+use std::io::{self, Write};
+use std::process::exit;
+
+type Row = [Pixel; LED_COUNT];
+
+/// Keeps the last frame written to the chain.
+struct Recorder<'a>(&'a Cell<Row>);
+
+impl SmartLedsWrite for Recorder<'_> {
+    type Error = Infallible;
+    type Color = Pixel;
+
+    fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+    where
+        T: Iterator<Item = I>,
+        I: Into<Self::Color>,
+    {
+        let mut row = self.0.get();
+        for (pixel, color) in row.iter_mut().zip(iterator) {
+            *pixel = color.into();
+        }
+        self.0.set(row);
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "ws2812b"))]
+fn channels(pixel: Pixel) -> Vec<u8> {
+    vec![pixel.r, pixel.g, pixel.b, pixel.a.0]
+}
+
+#[cfg(feature = "ws2812b")]
+fn channels(pixel: Pixel) -> Vec<u8> {
+    vec![pixel.r, pixel.g, pixel.b]
+}
+
+/// Approximates the pixel on screen, with white added to all three colors.
+fn screen(pixel: Pixel) -> [u8; 3] {
+    let channels = channels(pixel);
+    let white = channels.get(3).copied().unwrap_or(0);
+    [
+        channels[0].saturating_add(white),
+        channels[1].saturating_add(white),
+        channels[2].saturating_add(white),
+    ]
+}
+
+fn write_csv(out: &mut impl Write, rows: &[Row]) -> io::Result<()> {
+    let names = ["r", "g", "b", "w"];
+    let width = channels(Pixel::default()).len();
+    write!(out, "tick")?;
+    for n in 0..LED_COUNT {
+        for name in &names[..width] {
+            write!(out, ",{}{}", name, n)?;
+        }
+    }
+    writeln!(out)?;
+    for (tick, row) in rows.iter().enumerate() {
+        write!(out, "{}", tick)?;
+        for pixel in row {
+            for channel in channels(*pixel) {
+                write!(out, ",{}", channel)?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn write_ppm(out: &mut impl Write, rows: &[Row]) -> io::Result<()> {
+    write!(out, "P6\n{} {}\n255\n", LED_COUNT, rows.len())?;
+    for row in rows {
+        for pixel in row {
+            out.write_all(&screen(*pixel))?;
+        }
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("usage: render <mode> [effect] [--ticks N] [--fade MS] [--ppm]");
+    exit(2)
+}
+
+fn letter(arg: &str) -> Mode {
+    let mut chars = arg.chars();
+    match (chars.next().and_then(Mode::from_letter), chars.next()) {
+        (Some(mode), None) => mode,
+        _ => {
+            eprintln!("unknown mode {:?}", arg);
+            exit(2)
+        }
+    }
+}
+
+pub fn main() {
+    let mut letters = Vec::new();
+    let mut ticks = 600;
+    let mut fade = 0;
+    let mut ppm = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ticks" => {
+                ticks = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage(),
+                }
+            }
+            "--fade" => {
+                fade = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage(),
+                }
+            }
+            "--ppm" => ppm = true,
+            _ => letters.push(letter(&arg)),
+        }
+    }
+    let (mode, effect) = match letters[..] {
+        [mode] => (mode, None),
+        [mode, effect] => (mode, Some(effect)),
+        _ => usage(),
+    };
+
+    let last = Cell::new([Pixel::default(); LED_COUNT]);
+    let mut leds = Leds::new(Recorder(&last), Library::new());
+    leds.set_mode(mode, None);
+    if let Some(effect) = effect {
+        leds.show_effect(effect, 1, None);
+    }
+    // After the mode, so that only the end of the effect fades.
+    leds.set_fade(Duration::<u32, 1, 100>::millis(fade));
+    let rows: Vec<Row> = (0..ticks)
+        .map(|_| {
+            leds.tick();
+            last.get()
+        })
+        .collect();
+
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let written = if ppm {
+        write_ppm(&mut out, &rows)
+    } else {
+        write_csv(&mut out, &rows)
+    };
+    if let Err(e) = written.and_then(|_| out.flush()) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::easing::Easing;
+    use crate::led::Color;
+
+    const PERIOD: Duration<u32, 1, 100> = Duration::<u32, 1, 100>::millis(500);
+
+    /// Calls `script` before every tick and returns the recorded frames as CSV.
+    fn record(ticks: usize, mut script: impl FnMut(usize, &mut Leds<Recorder<'_>>)) -> String {
+        let last = Cell::new([Pixel::default(); LED_COUNT]);
+        let mut leds = Leds::new(Recorder(&last), Library::new());
+        let rows: Vec<Row> = (0..ticks)
+            .map(|tick| {
+                script(tick, &mut leds);
+                leds.tick();
+                last.get()
+            })
+            .collect();
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &rows).unwrap();
+        String::from_utf8(csv).unwrap()
+    }
+
+    /// Plays one mode from the start.
+    fn mode(ticks: usize, mode: Mode) -> String {
+        record(ticks, |tick, leds| {
+            if tick == 0 {
+                leds.set_mode(mode, None)
+            }
+        })
+    }
+
+    /// Compares with `golden/<chain>/<name>.csv`, recorded with the default `LED_COUNT`.
+    /// With `UPDATE_GOLDEN` set, writes the file instead.
+    fn golden(name: &str, csv: String) {
+        let chain = if cfg!(feature = "ws2812b") {
+            "rgb"
+        } else {
+            "rgbw"
+        };
+        let dir = format!("{}/golden/{}", env!("CARGO_MANIFEST_DIR"), chain);
+        let path = format!("{}/{}.csv", dir, name);
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(&path, csv).unwrap();
+        } else {
+            let expected =
+                std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+            assert!(
+                csv == expected,
+                "{} changed, rerun with UPDATE_GOLDEN=1 and diff",
+                path
+            );
+        }
+    }
+
+    fn upload(leds: &mut Leds<Recorder<'_>>) {
+        for keyframe in [
+            "0,ff000000,f,a,a",
+            "1,0000ff00,3c,14,a",
+            "2,000000ff,ff,a,14,1",
+        ] {
+            assert!(leds.load_keyframe(keyframe.parse().unwrap()));
+        }
+    }
+
+    #[test]
+    fn constant() {
+        golden(
+            "constant",
+            mode(10, Mode::Constant(Color::Rgbw(0x40_80_c0_20))),
+        );
+    }
+
+    #[test]
+    fn blink() {
+        golden("blink", mode(100, Mode::Blink(Color::Red, PERIOD)));
+    }
+
+    #[test]
+    fn pulse() {
+        let on = Duration::<u32, 1, 100>::millis(60);
+        golden("pulse", mode(100, Mode::Pulse(Color::Green, PERIOD, on, 2)));
+    }
+
+    #[test]
+    fn glow() {
+        for (name, easing) in [
+            ("glow_linear", Easing::Linear),
+            ("glow_sine", Easing::Sine),
+            ("glow_quadratic", Easing::Quadratic),
+            ("glow_breathing", Easing::Breathing),
+            ("glow_saw_up", Easing::SawUp),
+            ("glow_saw_down", Easing::SawDown),
+        ] {
+            golden(name, mode(50, Mode::Glow(Color::Cyan, PERIOD, easing)));
+        }
+    }
+
+    #[test]
+    fn chase() {
+        golden("chase", mode(50, Mode::Chase(Color::White, PERIOD, 3)));
+    }
+
+    #[test]
+    fn rainbow() {
+        golden("rainbow", mode(50, Mode::Rainbow(PERIOD, false)));
+        golden("rainbow_spread", mode(50, Mode::Rainbow(PERIOD, true)));
+    }
+
+    #[test]
+    fn custom() {
+        golden(
+            "custom",
+            record(120, |tick, leds| {
+                if tick == 0 {
+                    upload(leds);
+                    leds.set_mode(Mode::Custom, None);
+                }
+            }),
+        );
+    }
+
+    #[test]
+    fn pattern() {
+        golden(
+            "pattern",
+            record(120, |tick, leds| {
+                if tick == 0 {
+                    upload(leds);
+                    assert!(leds.store_pattern(3));
+                    leds.set_mode(Mode::Pattern(3), None);
+                }
+            }),
+        );
+    }
+
+    #[cfg(not(feature = "full-self-test"))]
+    #[test]
+    fn self_test() {
+        golden("self_test", mode(110, Mode::SelfTest));
+    }
+
+    /// A blink played twice over a glow, then the glow alone again.
+    #[test]
+    fn effect_end() {
+        golden(
+            "effect_end",
+            record(150, |tick, leds| {
+                if tick == 0 {
+                    leds.set_mode(Mode::Glow(Color::Blue, PERIOD, Easing::Linear), None);
+                    assert!(leds.show_effect(Mode::Blink(Color::Yellow, PERIOD), 2, None));
+                    leds.set_fade(Duration::<u32, 1, 100>::millis(200));
+                }
+            }),
+        );
+    }
+
+    #[test]
+    fn crossfade() {
+        golden(
+            "crossfade",
+            record(60, |tick, leds| match tick {
+                0 => {
+                    leds.set_mode(Mode::Constant(Color::Red), None);
+                    leds.set_fade(Duration::<u32, 1, 100>::millis(300));
+                }
+                20 => leds.set_mode(Mode::Blink(Color::Blue, PERIOD), None),
+                _ => {}
+            }),
+        );
+    }
+}
@@ -46,6 +46,7 @@ pub struct Rs485 {
     bus_busy: bool,
     token: Token,
     alone_cycles: u32,
+    errors: u16,
 }
 
 impl Rs485 {
@@ -76,9 +77,15 @@ impl Rs485 {
             bus_busy: true,
             token: Token::Unknown(0),
             alone_cycles: 0,
+            errors: 0,
         }
     }
 
+    /// Receive errors since startup, mostly bus collisions.
+    pub fn errors(&self) -> u16 {
+        self.errors
+    }
+
     pub fn interrupt(
         &mut self,
         timer: bool,
@@ -124,6 +131,7 @@ impl Rs485 {
                     // Bad news, perhaps we have a bus collision.
                     // First, stop any ongoing transmission - NOW.
                     self.tx_dma.disable();
+                    self.errors = self.errors.wrapping_add(1);
                     // Errors leave a corrupted value in the RX register and leave
                     // the interrupt flag in active state, so read-out and ignore
                     // any leftovers we have.
@@ -0,0 +1,110 @@
+//! Smart LED chain on USART2 TX, fed by DMA.
+//!
+//! The UART runs at 3.75 Mbaud with inverted TX, so every UART frame (start bit, 8 data
+//! bits, stop bit) carries two LED bits of five UART bits each.
+
+use crate::hal::{
+    dma::{self, Channel, Target},
+    serial::{BasicConfig, Serial, Tx},
+    stm32::USART2,
+};
+use crate::led::Pixel;
+use crate::LED_COUNT;
+use core::ptr::addr_of_mut;
+use smart_leds::SmartLedsWrite;
+
+type UART = USART2;
+type UARTTX = Tx<UART, BasicConfig>;
+type DMA = dma::C2;
+
+#[cfg(not(feature = "ws2812b"))]
+const PIXEL_BYTES: usize = 4;
+#[cfg(feature = "ws2812b")]
+const PIXEL_BYTES: usize = 3;
+
+const UART_BYTES_PER_BYTE: usize = 4;
+const BUF_LEN: usize = LED_COUNT * PIXEL_BYTES * UART_BYTES_PER_BYTE;
+
+/// UART data for two LED bits, first bit in the higher position.
+const BIT_PAIRS: [u8; 4] = [0xef, 0x8f, 0xec, 0x8c];
+
+#[derive(Debug)]
+pub struct Busy;
+
+pub struct Ws2812Dma {
+    _tx: UARTTX,
+    tx_dma: DMA,
+}
+
+#[cfg(not(feature = "ws2812b"))]
+fn wire_order(pixel: Pixel) -> [u8; PIXEL_BYTES] {
+    [pixel.g, pixel.r, pixel.b, pixel.a.0]
+}
+
+#[cfg(feature = "ws2812b")]
+fn wire_order(pixel: Pixel) -> [u8; PIXEL_BYTES] {
+    [pixel.g, pixel.r, pixel.b]
+}
+
+fn encode(byte: u8, out: &mut [u8]) {
+    for (n, o) in out.iter_mut().enumerate() {
+        *o = BIT_PAIRS[((byte >> (6 - 2 * n)) & 0b11) as usize];
+    }
+}
+
+impl Ws2812Dma {
+    pub fn new(uart: Serial<UART, BasicConfig>, mut tx_dma: DMA) -> Self {
+        let (mut tx, _) = uart.split();
+
+        unsafe {
+            tx_dma.set_direction(dma::Direction::FromMemory);
+            tx_dma.set_peripheral_address(&(*UART::ptr()).tdr as *const _ as u32, false);
+            tx_dma.select_peripheral(tx.dmamux());
+            tx.enable_dma();
+        }
+
+        Self { _tx: tx, tx_dma }
+    }
+}
+
+impl SmartLedsWrite for Ws2812Dma {
+    type Error = Busy;
+    type Color = Pixel;
+
+    /// Encodes the frame and starts the transfer without waiting for it to finish.
+    fn write<T, I>(&mut self, iterator: T) -> Result<(), Self::Error>
+    where
+        T: Iterator<Item = I>,
+        I: Into<Self::Color>,
+    {
+        static mut BUF: [u8; BUF_LEN] = [0; BUF_LEN];
+
+        if self.tx_dma.get_transfer_remaining() != 0 {
+            return Err(Busy);
+        }
+        // Only touched here, and only while no transfer reads it.
+        let buf = unsafe { &mut *addr_of_mut!(BUF) };
+
+        unsafe {
+            let mut len = 0;
+            let chunks = buf.chunks_exact_mut(PIXEL_BYTES * UART_BYTES_PER_BYTE);
+            for (pixel, chunk) in iterator.zip(chunks) {
+                let bytes = wire_order(pixel.into());
+                for (byte, out) in bytes
+                    .iter()
+                    .zip(chunk.chunks_exact_mut(UART_BYTES_PER_BYTE))
+                {
+                    encode(*byte, out);
+                }
+                len += chunk.len();
+            }
+
+            self.tx_dma.disable();
+            self.tx_dma.set_memory_address(buf.as_ptr() as u32, true);
+            self.tx_dma.set_transfer_length(len as u16);
+            self.tx_dma.enable();
+        }
+
+        Ok(())
+    }
+}